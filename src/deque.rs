@@ -0,0 +1,367 @@
+//! A self-contained Chase-Lev work-stealing deque.
+//!
+//! `queue.rs` used to depend on `std::sync::deque`, an internal standard
+//! library module that was later pulled out into a standalone `deque`
+//! crate (and eventually removed from std altogether). Vendoring our own
+//! copy here drops that dependency and gives us control over the buffer
+//! growth policy.
+//!
+//! A single owner thread calls `push`/`pop` at the bottom of the deque;
+//! any number of thief threads call `steal` from the top. The structure
+//! is two atomic indices, `bottom` and `top`, over a growable ring buffer
+//! whose capacity is always a power of two, so indexing is a mask rather
+//! than a modulo.
+
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicInt, AtomicPtr, SeqCst};
+
+/// New deques start with room for `1 << MIN_LOG_CAPACITY` items.
+const MIN_LOG_CAPACITY: uint = 5;
+
+/// The result of a `steal`.
+pub enum Stolen<T> {
+    /// The deque was empty.
+    Empty,
+    /// Lost a race with another thief (or the owner popping the last
+    /// item); the caller should try again.
+    Abort,
+    /// Successfully took an item.
+    Data(T),
+}
+
+pub use self::Stolen::{Abort, Data, Empty};
+
+/// The backing ring buffer for a deque. `log_size` is the base-2 log of
+/// the slot count, so the capacity and mask both fall out of a shift.
+struct Buffer<T> {
+    storage: *mut T,
+    log_size: uint,
+}
+
+impl<T: Send> Buffer<T> {
+    fn new(log_size: uint) -> Buffer<T> {
+        let mut storage: Vec<T> = Vec::with_capacity(1u << log_size);
+        let ptr = storage.as_mut_ptr();
+        // `storage` is length 0, so forgetting it leaks only the raw
+        // allocation, not any elements; we reclaim that allocation by
+        // hand in `Buffer`'s `Drop` impl.
+        unsafe { mem::forget(storage) }
+        Buffer { storage: ptr, log_size: log_size }
+    }
+
+    fn size(&self) -> int { (1u << self.log_size) as int }
+    fn mask(&self) -> int { self.size() - 1 }
+
+    unsafe fn get(&self, i: int) -> T {
+        use std::ptr::read;
+        read(self.storage.offset((i & self.mask()) as int) as *const T)
+    }
+
+    unsafe fn put(&self, i: int, t: T) {
+        use std::ptr::write;
+        write(self.storage.offset((i & self.mask()) as int), t);
+    }
+
+    /// Builds a buffer twice the size of this one, holding the same live
+    /// elements (`top` inclusive through `bottom` exclusive).
+    unsafe fn grow(&self, bottom: int, top: int) -> Buffer<T> {
+        let new_buf = Buffer::new(self.log_size + 1);
+        let mut i = top;
+        while i != bottom {
+            new_buf.put(i, self.get(i));
+            i += 1;
+        }
+        new_buf
+    }
+}
+
+impl<T> Drop for Buffer<T> {
+    fn drop(&mut self) {
+        // Any slots still logically holding live data were already moved
+        // out (via `get`) by whichever `push`/`pop`/`steal`/`grow` last
+        // touched them, so this just reclaims the raw storage without
+        // running element destructors.
+        unsafe {
+            drop(Vec::from_raw_parts(self.storage, 0, 1u << self.log_size));
+        }
+    }
+}
+
+/// A pool that keeps retired buffers alive for the lifetime of the
+/// queues built from it.
+///
+/// When a deque outgrows its buffer it atomically swaps in a bigger one,
+/// but a thief racing the swap may still be mid-read against the old
+/// buffer. Rather than track readers, the pool simply never frees a
+/// retired buffer: cheap, and more than acceptable for a deque that lives
+/// as long as the work queue it backs.
+pub struct BufferPool<T> {
+    retired: Arc<Mutex<Vec<Box<Buffer<T>>>>>,
+}
+
+impl<T: Send> BufferPool<T> {
+    /// Creates a new, empty buffer pool.
+    pub fn new() -> BufferPool<T> {
+        BufferPool { retired: Arc::new(Mutex::new(vec!())) }
+    }
+
+    /// Creates a new empty deque, returning its owner and thief ends.
+    pub fn deque(&self) -> (Worker<T>, Stealer<T>) {
+        let deque = Arc::new(Deque::new(self.clone()));
+        (Worker { deque: deque.clone() }, Stealer { deque: deque })
+    }
+
+    fn retire(&self, buf: Box<Buffer<T>>) {
+        self.retired.lock().push(buf);
+    }
+}
+
+impl<T> Clone for BufferPool<T> {
+    fn clone(&self) -> BufferPool<T> {
+        BufferPool { retired: self.retired.clone() }
+    }
+}
+
+struct Deque<T> {
+    bottom: AtomicInt,
+    top: AtomicInt,
+    buffer: AtomicPtr<Buffer<T>>,
+    pool: BufferPool<T>,
+}
+
+unsafe impl<T: Send> Send for Deque<T> {}
+unsafe impl<T: Send> Sync for Deque<T> {}
+
+impl<T: Send> Drop for Deque<T> {
+    fn drop(&mut self) {
+        unsafe {
+            let buf = self.buffer.load(SeqCst);
+            let top = self.top.load(SeqCst);
+            let bottom = self.bottom.load(SeqCst);
+
+            // Run the destructor for every element still resident between
+            // `top` and `bottom`; everything outside that range was
+            // already moved out by a `push`/`pop`/`steal`/`grow`.
+            let mut i = top;
+            while i != bottom {
+                drop((*buf).get(i));
+                i += 1;
+            }
+
+            // Reclaim the current buffer's raw storage (its own `Drop`
+            // only frees memory, since we've just drained any live
+            // elements above). Retired buffers are released in turn as
+            // `pool` drops here and its `Arc` count eventually reaches
+            // zero; `grow` already copied every live element out of them,
+            // so they have nothing left to drain.
+            drop(Box::from_raw(buf));
+        }
+    }
+}
+
+impl<T: Send> Deque<T> {
+    fn new(pool: BufferPool<T>) -> Deque<T> {
+        let buf = Box::new(Buffer::new(MIN_LOG_CAPACITY));
+        Deque {
+            bottom: AtomicInt::new(0),
+            top: AtomicInt::new(0),
+            buffer: AtomicPtr::new(unsafe { mem::transmute(buf) }),
+            pool: pool,
+        }
+    }
+
+    unsafe fn push(&self, t: T) {
+        let b = self.bottom.load(SeqCst);
+        let top = self.top.load(SeqCst);
+        let mut buf: *const Buffer<T> = self.buffer.load(SeqCst) as *const Buffer<T>;
+
+        if b - top >= (*buf).size() - 1 {
+            self.grow(b, top);
+            buf = self.buffer.load(SeqCst) as *const Buffer<T>;
+        }
+
+        (*buf).put(b, t);
+        // `store` with `SeqCst` is a full fence, so every thief that
+        // later observes `bottom == b + 1` also observes this write.
+        self.bottom.store(b + 1, SeqCst);
+    }
+
+    unsafe fn grow(&self, bottom: int, top: int) {
+        let old = self.buffer.load(SeqCst);
+        let new_buf = Box::new((*old).grow(bottom, top));
+        self.buffer.store(mem::transmute(new_buf), SeqCst);
+        self.pool.retire(mem::transmute(old));
+    }
+
+    unsafe fn pop(&self) -> Option<T> {
+        let b = self.bottom.load(SeqCst) - 1;
+        let buf = self.buffer.load(SeqCst);
+        self.bottom.store(b, SeqCst);
+        let top = self.top.load(SeqCst);
+
+        if b < top {
+            // The deque was already empty; put `bottom` back.
+            self.bottom.store(top, SeqCst);
+            return None;
+        }
+
+        let mut data = Some((*buf).get(b));
+        if b == top {
+            // This was the last item: race any thief trying to steal it.
+            if self.top.compare_and_swap(top, top + 1, SeqCst) != top {
+                // Lost the race; the value now belongs to the thief that
+                // won, so don't let our copy's destructor run.
+                if let Some(lost) = data.take() {
+                    mem::forget(lost);
+                }
+            }
+            self.bottom.store(top + 1, SeqCst);
+        }
+        data
+    }
+
+    unsafe fn steal(&self) -> Stolen<T> {
+        let top = self.top.load(SeqCst);
+        let b = self.bottom.load(SeqCst);
+
+        if top >= b {
+            return Empty;
+        }
+
+        let buf = self.buffer.load(SeqCst);
+        let data = (*buf).get(top);
+        if self.top.compare_and_swap(top, top + 1, SeqCst) == top {
+            Data(data)
+        } else {
+            // Lost the race; don't drop a value we never actually claimed.
+            mem::forget(data);
+            Abort
+        }
+    }
+}
+
+/// The owning end of a deque. Only the thread that created it may
+/// `push`/`pop`.
+pub struct Worker<T> {
+    deque: Arc<Deque<T>>,
+}
+
+impl<T: Send> Worker<T> {
+    /// Pushes an item onto the bottom of the deque.
+    #[inline]
+    pub fn push(&mut self, t: T) {
+        unsafe { self.deque.push(t) }
+    }
+
+    /// Pops an item off the bottom of the deque, if one is available.
+    #[inline]
+    pub fn pop(&mut self) -> Option<T> {
+        unsafe { self.deque.pop() }
+    }
+}
+
+/// The thief end of a deque. Any number of threads may hold (cloned)
+/// stealers and call `steal` concurrently with each other and with the
+/// owner's `push`/`pop`.
+pub struct Stealer<T> {
+    deque: Arc<Deque<T>>,
+}
+
+impl<T: Send> Stealer<T> {
+    /// Attempts to steal an item off the top of the deque.
+    #[inline]
+    pub fn steal(&self) -> Stolen<T> {
+        unsafe { self.deque.steal() }
+    }
+}
+
+impl<T: Send> Clone for Stealer<T> {
+    fn clone(&self) -> Stealer<T> {
+        Stealer { deque: self.deque.clone() }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Abort, BufferPool, Data, Empty};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUint, SeqCst};
+
+    #[test]
+    fn push_then_pop_sees_every_item() {
+        let pool = BufferPool::new();
+        let (mut worker, _stealer) = pool.deque();
+
+        for i in range(0u, 1000) {
+            worker.push(i);
+        }
+
+        let mut popped = 0u;
+        while worker.pop().is_some() {
+            popped += 1;
+        }
+        assert_eq!(popped, 1000);
+    }
+
+    #[test]
+    fn pop_survives_a_grow() {
+        // MIN_LOG_CAPACITY gives the deque 32 slots to start with, so
+        // this forces at least one grow.
+        let pool = BufferPool::new();
+        let (mut worker, _stealer) = pool.deque();
+
+        for i in range(0u, 500) {
+            worker.push(i);
+        }
+
+        let mut sum = 0u;
+        while let Some(i) = worker.pop() {
+            sum += i;
+        }
+        assert_eq!(sum, range(0u, 500).fold(0u, |a, b| a + b));
+    }
+
+    #[test]
+    fn owner_pops_and_thieves_steal_disjoint_items() {
+        let pool = BufferPool::new();
+        let (mut worker, stealer) = pool.deque();
+
+        let total = 20000u;
+        for i in range(0u, total) {
+            worker.push(i);
+        }
+
+        let stolen_count = Arc::new(AtomicUint::new(0));
+        let mut thief_ports = vec!();
+        for _ in range(0u, 4u) {
+            let thief = stealer.clone();
+            let stolen_count = stolen_count.clone();
+            let (done_tx, done_rx) = channel();
+            spawn(proc() {
+                loop {
+                    match thief.steal() {
+                        Data(_) => { stolen_count.fetch_add(1, SeqCst); }
+                        Empty => break,
+                        Abort => {}
+                    }
+                }
+                done_tx.send(());
+            });
+            thief_ports.push(done_rx);
+        }
+
+        let mut owner_popped = 0u;
+        while worker.pop().is_some() {
+            owner_popped += 1;
+        }
+
+        for port in thief_ports.into_iter() {
+            let _ = port.recv_opt();
+        }
+
+        // Every item pushed was either popped by the owner or stolen by
+        // exactly one thief; none should be lost or double-counted.
+        assert_eq!(owner_popped + stolen_count.load(SeqCst), total);
+    }
+}
@@ -5,8 +5,12 @@
 //!
 //! Extracted from Cargo and Servo for general use.
 
+extern crate native;
 extern crate rand;
 
+pub use pool::TaskPool;
 pub use queue::WorkQueue;
+mod deque;
+pub mod pool;
 pub mod queue;
 
@@ -4,11 +4,20 @@
 //! Unlike std::sync::TaskPool, work registered on this pool can be
 //! used by any of the waiting tasks.
 
+use std::any::Any;
 use std::sync::{Arc, Mutex};
+use std::task;
 
 /// A load-balancing task pool.
+///
+/// Dropping a `TaskPool` closes its job queue and blocks until every worker
+/// task has drained its remaining jobs and exited. Call `detach` instead if
+/// the old fire-and-forget behavior is wanted.
 pub struct TaskPool {
-    tx: Sender<proc(): Send>
+    tx: Option<Sender<proc(): Send>>,
+    // One receiver per worker task; each closes (and so unblocks `recv_opt`)
+    // only once its worker has actually returned.
+    workers: Vec<Receiver<()>>,
 }
 
 impl TaskPool {
@@ -21,31 +30,68 @@ impl TaskPool {
         assert!(tasks > 0);
 
         let (tx, rx) = channel::<proc(): Send>();
+        let state = Arc::new(Mutex::new(rx));
 
-        // Initialize the task pool in another thread.
-        spawn(proc() {
-            let state = Arc::new(Mutex::new(rx));
-
-            for _ in range(0, tasks) {
-                let rx = state.clone();
-                spawn(proc() {
-                    loop {
-                        let job = rx.lock().recv_opt();
-                        match job {
-                            Ok(job) => job(),
-                            Err(..) => break
-                        }
+        let workers = range(0, tasks).map(|_| {
+            let rx = state.clone();
+            let (done_tx, done_rx) = channel::<()>();
+            spawn(proc() {
+                let _done_tx = done_tx;
+                loop {
+                    let job = rx.lock().recv_opt();
+                    match job {
+                        Ok(job) => job(),
+                        Err(..) => break
                     }
-                });
-            }
-        });
+                }
+            });
+            done_rx
+        }).collect();
 
-        TaskPool { tx: tx }
+        TaskPool { tx: Some(tx), workers: workers }
     }
 
     /// Run this proc in any of the tasks in the pool.
     pub fn execute(&self, job: proc(): Send) {
-        self.tx.send(job);
+        drop(self.try_execute(job));
+    }
+
+    /// Run this proc in any of the tasks in the pool, returning a `Receiver`
+    /// on which the result can be collected.
+    ///
+    /// The job runs under an isolation boundary that catches unwinding, so a
+    /// panicking job reports `Err` on the returned channel instead of taking
+    /// down the worker task that ran it; the pool keeps its full worker
+    /// count no matter how many jobs panic.
+    pub fn try_execute<T: Send>(&self, job: proc(): Send -> T)
+                                 -> Receiver<Result<T, Box<Any + Send>>> {
+        let (tx, rx) = channel();
+        self.tx.as_ref().unwrap().send(proc() {
+            let _ = tx.send(task::try(job));
+        });
+        rx
+    }
+
+    /// Leave the worker tasks running in the background instead of joining
+    /// them.
+    ///
+    /// The job queue is still closed, so each worker exits on its own once
+    /// its backlog drains; this just stops `drop` from waiting around for
+    /// that to happen.
+    pub fn detach(mut self) {
+        drop(self.tx.take());
+        self.workers.clear();
+    }
+}
+
+impl Drop for TaskPool {
+    fn drop(&mut self) {
+        // Closing the queue lets every worker's `recv_opt` loop end once it
+        // has drained whatever work was already sent.
+        drop(self.tx.take());
+        for worker in self.workers.iter() {
+            let _ = worker.recv_opt();
+        }
     }
 }
 
@@ -13,10 +13,47 @@ use std::time::duration::Duration;
 use rand::{Rng, XorShiftRng};
 use std::rand::weak_rng;
 
+use std::any::Any;
 use std::mem;
+use std::rt::unwind;
 use std::sync::atomic::{AtomicUint, SeqCst};
-use std::sync::deque::{Abort, BufferPool, Data, Empty, Stealer, Worker};
+use std::task;
+use std::task::TaskBuilder;
+
+use deque::{Abort, BufferPool, Data, Empty, Stealer, Worker};
+use native::task::NativeTaskBuilder;
+
+/// Selects how `WorkQueue::with_config` spawns its worker threads.
+pub enum SpawnStrategy {
+    /// Spawn each worker as a dedicated native OS thread, pinned for the
+    /// life of the queue. Appropriate for CPU-bound fork-join workloads,
+    /// where workers spin-steal and micro-`sleep` rather than yielding to
+    /// an M:N scheduler.
+    Native,
+    /// Spawn each worker as an ordinary (green/runtime-scheduled) task.
+    /// This is the default.
+    Task,
+}
+
+/// Configuration for `WorkQueue::with_config`.
+pub struct WorkQueueConfig {
+    /// How each worker thread is spawned.
+    pub spawn_strategy: SpawnStrategy,
+    /// Name prefix given to each worker (e.g. `"workqueue"` yields threads
+    /// named `"workqueue-0"`, `"workqueue-1"`, ...), so they're visible by
+    /// name in profilers and debuggers. `None` leaves workers unnamed.
+    pub thread_name_prefix: Option<String>,
+}
 
+impl WorkQueueConfig {
+    /// The default configuration: task-spawned, unnamed workers.
+    pub fn new() -> WorkQueueConfig {
+        WorkQueueConfig {
+            spawn_strategy: SpawnStrategy::Task,
+            thread_name_prefix: None,
+        }
+    }
+}
 
 /// A unit of work.
 ///
@@ -44,7 +81,11 @@ enum WorkerMsg<QueueData, WorkData> {
 /// Messages to the supervisor
 enum SupervisorMsg<QueueData, WorkData> {
     Finished,
-    ReturnDeque(uint, Worker<WorkUnit<QueueData, WorkData>>)
+    ReturnDeque(uint, Worker<WorkUnit<QueueData, WorkData>>),
+    /// A work unit run by the worker at the given index panicked; carries
+    /// the cause so the supervisor can surface it instead of silently
+    /// losing the work unit (and, without this, the worker thread too).
+    Panicked(uint, Box<Any + Send>),
 }
 
 /// Information that the supervisor thread keeps about the worker threads.
@@ -74,6 +115,12 @@ struct WorkerThread<QueueData, WorkData> {
 const SPIN_COUNT: u32 = 128;
 const SPINS_UNTIL_BACKOFF: u32 = 100;
 const BACKOFF_INCREMENT_IN_US: u32 = 5;
+/// Ceiling on `back_off_sleep`. Without one, a worker idle for a long
+/// stretch keeps lengthening its own nap between `port.try_recv()` checks,
+/// so the time before it would notice a `Stop`/`Exit` (or, in streaming
+/// mode, simply get back around to stealing) grows without bound. Capping
+/// it keeps that latency bounded no matter how long the worker's been idle.
+const MAX_BACKOFF_IN_US: u32 = 1000;
 
 impl<QueueData: Send, WorkData: Send> WorkerThread<QueueData, WorkData> {
     /// The main logic. This function starts up the worker and listens for
@@ -119,7 +166,9 @@ impl<QueueData: Send, WorkData: Send> WorkerThread<QueueData, WorkData> {
 
                             if i > SPINS_UNTIL_BACKOFF {
                                 sleep(Duration::microseconds(back_off_sleep as i64));
-                                back_off_sleep += BACKOFF_INCREMENT_IN_US;
+                                if back_off_sleep < MAX_BACKOFF_IN_US {
+                                    back_off_sleep += BACKOFF_INCREMENT_IN_US;
+                                }
                             }
 
                             if i == SPIN_COUNT {
@@ -129,7 +178,7 @@ impl<QueueData: Send, WorkData: Send> WorkerThread<QueueData, WorkData> {
                                         break
                                     }
                                     Ok(WorkerMsg::Exit) => return,
-                                    Ok(_) => panic!("unexpected message"),
+                                    Ok(WorkerMsg::Start(..)) => panic!("unexpected start message"),
                                     _ => {}
                                 }
 
@@ -145,21 +194,44 @@ impl<QueueData: Send, WorkData: Send> WorkerThread<QueueData, WorkData> {
                     }
                 }
 
-                // At this point, we have some work. Perform it.
+                // At this point, we have some work. Perform it under an
+                // unwinding boundary so that a panicking work unit can't
+                // take the worker thread down with it: without this, the
+                // thread would die before reaching the `fetch_sub` and
+                // `ReturnDeque` below, and `WorkQueue::run` would block
+                // forever waiting on them.
+                //
+                // We deliberately use `unwind::try` rather than
+                // `task::try` here: `task::try` isolates panics by
+                // spawning a whole new task per call, which is far too
+                // expensive on this per-work-unit hot path. `unwind::try`
+                // catches the unwind in place on the current stack, so it
+                // also doesn't need its closure to be `Send`/`'static` —
+                // `proxy` can borrow `deque` directly instead of going
+                // through a raw pointer.
                 let mut proxy = WorkerProxy {
                     worker: &mut deque,
                     ref_count: ref_count,
                     queue_data: queue_data,
                 };
-                (work_unit.fun)(work_unit.data, &mut proxy);
+                let fun = work_unit.fun;
+                let data = work_unit.data;
+                let result = unsafe {
+                    unwind::try(|| fun(data, &mut proxy))
+                };
 
-                // The work is done. Now decrement the count of outstanding work items. If this was
-                // the last work unit in the queue, then send a message on the channel.
+                // The work is done (or panicked). Now decrement the count of outstanding work
+                // items. If this was the last work unit in the queue, then send a message on
+                // the channel regardless of whether it succeeded.
                 unsafe {
                     if (*ref_count).fetch_sub(1, SeqCst) == 1 {
                         self.chan.send(SupervisorMsg::Finished)
                     }
                 }
+
+                if let Err(cause) = result {
+                    self.chan.send(SupervisorMsg::Panicked(self.index, cause));
+                }
             }
 
             // Give the deque back to the supervisor.
@@ -198,15 +270,40 @@ pub struct WorkQueue<QueueData, WorkData> {
     port: Receiver<SupervisorMsg<QueueData, WorkData>>,
     /// The amount of work that has been enqueued.
     work_count: uint,
+    /// The outstanding-work count shared with the workers for the
+    /// in-progress (or most recent) `run`/`start` round. Lives on `self`
+    /// rather than on the stack so a streaming `RunningQueue` can keep
+    /// handing workers a stable pointer to it across many `submit` calls.
+    running_count: AtomicUint,
+    /// One join handle per worker thread, used by `shutdown` to wait for
+    /// every thread to actually terminate and to learn about any that
+    /// panicked.
+    threads: Vec<Receiver<Result<(), Box<Any + Send>>>>,
+    /// The owning end of a deque wired into every worker's `other_deques`
+    /// as just another steal victim. `RunningQueue::submit` pushes here
+    /// directly, so a submitted unit is reachable by any idle worker the
+    /// instant it's pushed, rather than depending on whichever single
+    /// worker a message happened to address, or on that worker's
+    /// `port.try_recv()` checkpoint.
+    injector: Worker<WorkUnit<QueueData, WorkData>>,
     /// Arbitrary user data.
     pub data: QueueData,
 }
 
 impl<QueueData: Send, WorkData: Send> WorkQueue<QueueData, WorkData> {
     /// Creates a new work queue and spawns all the threads associated with
-    /// it.
+    /// it, using the default spawn strategy (task-scheduled, unnamed
+    /// workers).
     pub fn new(thread_count: uint,
                user_data: QueueData) -> WorkQueue<QueueData, WorkData> {
+        WorkQueue::with_config(thread_count, user_data, WorkQueueConfig::new())
+    }
+
+    /// Creates a new work queue and spawns all the threads associated with
+    /// it, as directed by `config`.
+    pub fn with_config(thread_count: uint,
+                        user_data: QueueData,
+                        config: WorkQueueConfig) -> WorkQueue<QueueData, WorkData> {
         // Set up data structures.
         let (supervisor_chan, supervisor_port) = channel();
         let (mut infos, mut threads) = (vec!(), vec!());
@@ -237,18 +334,45 @@ impl<QueueData: Send, WorkData: Send> WorkQueue<QueueData, WorkData> {
             }
         }
 
-        // Spawn threads.
-        for thread in threads.into_iter() {
-            spawn(proc() {
+        // Wire a dedicated injector deque into every worker's steal
+        // candidates so `submit` has somewhere to put work that's visible
+        // to all of them at once, instead of needing to pick one.
+        let injector_pool = BufferPool::new();
+        let (injector, injector_thief) = injector_pool.deque();
+        for thread in threads.iter_mut() {
+            thread.other_deques.push(injector_thief.clone());
+        }
+
+        // Spawn threads, keeping a join handle for each so `shutdown` can
+        // wait for them to terminate and learn about any that panicked.
+        let mut thread_ports = vec!();
+        for (i, thread) in threads.into_iter().enumerate() {
+            let (done_tx, done_rx) = channel();
+            let mut builder = TaskBuilder::new();
+            if let Some(ref prefix) = config.thread_name_prefix {
+                builder = builder.named(format!("{}-{}", prefix, i));
+            }
+            let body = proc() {
                 let mut thread = thread;
-                thread.start()
-            })
+                done_tx.send(task::try(proc() { thread.start() }));
+            };
+            match config.spawn_strategy {
+                // Pin the worker to a dedicated OS thread: CPU-bound
+                // fork-join workers that spin-steal and micro-sleep don't
+                // play well with an M:N scheduler.
+                SpawnStrategy::Native => builder.native().spawn(body),
+                SpawnStrategy::Task => builder.spawn(body),
+            }
+            thread_ports.push(done_rx);
         }
 
         WorkQueue {
             workers: infos,
             port: supervisor_port,
             work_count: 0,
+            running_count: AtomicUint::new(0),
+            threads: thread_ports,
+            injector: injector,
             data: user_data,
         }
     }
@@ -266,16 +390,29 @@ impl<QueueData: Send, WorkData: Send> WorkQueue<QueueData, WorkData> {
     }
 
     /// Synchronously runs all the enqueued tasks and waits for them to complete.
-    pub fn run(&mut self) {
+    ///
+    /// If any work unit panicked during this fork-join round, `Err` carries
+    /// the cause of every one of them; the round still runs to completion
+    /// and every deque is still reclaimed either way.
+    pub fn run(&mut self) -> Result<(), Vec<Box<Any + Send>>> {
         // Tell the workers to start.
-        let mut work_count = AtomicUint::new(self.work_count);
+        self.running_count = AtomicUint::new(self.work_count);
         for worker in self.workers.iter_mut() {
             worker.chan.send(WorkerMsg::Start(worker.deque.take().unwrap(),
-                                              &mut work_count, &self.data))
+                                              &mut self.running_count, &self.data))
         }
 
-        // Wait for the work to finish.
-        drop(self.port.recv());
+        let mut panics = vec!();
+
+        // Wait for the work to finish, noting the cause of any work unit
+        // that panicked along the way.
+        loop {
+            match self.port.recv() {
+                SupervisorMsg::Finished => break,
+                SupervisorMsg::Panicked(_, cause) => panics.push(cause),
+                SupervisorMsg::ReturnDeque(..) => panic!("unexpected deque return!"),
+            }
+        }
         self.work_count = 0;
 
         // Tell everyone to stop.
@@ -283,20 +420,213 @@ impl<QueueData: Send, WorkData: Send> WorkQueue<QueueData, WorkData> {
             worker.chan.send(WorkerMsg::Stop)
         }
 
-        // Get our deques back.
-        for _ in range(0, self.workers.len()) {
+        // Get our deques back, still watching for any late-arriving panics.
+        let mut deques_remaining = self.workers.len();
+        while deques_remaining > 0 {
             match self.port.recv() {
-                SupervisorMsg::ReturnDeque(index, deque) => self.workers[index].deque = Some(deque),
+                SupervisorMsg::ReturnDeque(index, deque) => {
+                    self.workers[index].deque = Some(deque);
+                    deques_remaining -= 1;
+                }
+                SupervisorMsg::Panicked(_, cause) => panics.push(cause),
                 SupervisorMsg::Finished => panic!("unexpected finished message!"),
             }
         }
+
+        if panics.is_empty() { Ok(()) } else { Err(panics) }
+    }
+
+    /// Shut down the work queue, blocking until every worker thread has
+    /// actually terminated.
+    ///
+    /// Returns the panic cause of every worker thread that panicked, so
+    /// callers can learn about failures instead of having them vanish along
+    /// with the thread.
+    pub fn shutdown(&mut self) -> Vec<Box<Any + Send>> {
+        for worker in self.workers.iter() {
+            worker.chan.send(WorkerMsg::Exit)
+        }
+
+        self.threads.iter().filter_map(|thread| {
+            match thread.recv_opt() {
+                Ok(Err(cause)) => Some(cause),
+                _ => None,
+            }
+        }).collect()
     }
 
-    /// Shutdown the workqueue.
-    pub fn shutdown(&mut self) {
+    /// Like `shutdown`, but returns immediately instead of waiting for the
+    /// worker threads to terminate.
+    pub fn shutdown_now(&mut self) {
         for worker in self.workers.iter() {
             worker.chan.send(WorkerMsg::Exit)
         }
     }
+
+    /// Puts the queue into streaming mode: tells every worker to `Start`
+    /// and keeps them hot rather than tearing down after a single batch,
+    /// so new work can be `submit`ted as it arrives instead of only being
+    /// `push`ed before the first `run`. Any work already `push`ed counts
+    /// toward the first `quiesce`/`drain`.
+    pub fn start(&mut self) -> RunningQueue<QueueData, WorkData> {
+        self.running_count = AtomicUint::new(self.work_count);
+        for worker in self.workers.iter_mut() {
+            worker.chan.send(WorkerMsg::Start(worker.deque.take().unwrap(),
+                                              &mut self.running_count, &self.data))
+        }
+        RunningQueue { queue: self, stopped: false }
+    }
+}
+
+/// A `WorkQueue` put into streaming mode by `start`. Unlike a plain
+/// fork-join `run`, a `RunningQueue`'s workers stay alive and ready to
+/// steal between bursts of work, so it can be reused as a long-lived
+/// work-stealing executor rather than a one-shot barrier.
+pub struct RunningQueue<'a, QueueData: 'a, WorkData: 'a> {
+    queue: &'a mut WorkQueue<QueueData, WorkData>,
+    /// Set once `reclaim` has run, so `stop` and `Drop` can share it
+    /// without stopping (and waiting for) every worker a second time.
+    stopped: bool,
+}
+
+impl<'a, QueueData: Send, WorkData: Send> RunningQueue<'a, QueueData, WorkData> {
+    /// Submits a work unit without blocking, bumping the shared
+    /// outstanding-work count so `quiesce`/`drain` and the usual
+    /// `Finished` bookkeeping see it.
+    ///
+    /// Pushes straight onto the queue's injector deque, which every
+    /// worker has wired in as a steal candidate, so the unit is
+    /// immediately stealable by whichever worker goes idle first rather
+    /// than sitting in one specific worker's mailbox until that worker
+    /// happens to check it.
+    pub fn submit(&mut self, work_unit: WorkUnit<QueueData, WorkData>) {
+        self.queue.running_count.fetch_add(1, SeqCst);
+        self.queue.injector.push(work_unit);
+    }
+
+    /// Blocks until the outstanding-work count hits zero, i.e. every work
+    /// unit pushed or submitted so far has completed. Unlike `run`, the
+    /// deques stay with the workers afterward and more work can still be
+    /// `submit`ted.
+    ///
+    /// Returns the cause of any work unit that panicked while quiescing.
+    pub fn quiesce(&mut self) -> Vec<Box<Any + Send>> {
+        let mut panics = vec!();
+        while self.queue.running_count.load(SeqCst) > 0 {
+            match self.queue.port.recv() {
+                SupervisorMsg::Finished => {}
+                SupervisorMsg::Panicked(_, cause) => panics.push(cause),
+                SupervisorMsg::ReturnDeque(..) => panic!("unexpected deque return!"),
+            }
+        }
+        panics
+    }
+
+    /// Alias for `quiesce`.
+    pub fn drain(&mut self) -> Vec<Box<Any + Send>> {
+        self.quiesce()
+    }
+
+    /// Stops every worker and reclaims its deque, ending the streaming
+    /// session and returning the queue to batched `push`/`run` mode.
+    ///
+    /// Returns the cause of any work unit that panicked while the queue
+    /// was running, mirroring `run`.
+    pub fn stop(mut self) -> Result<(), Vec<Box<Any + Send>>> {
+        self.reclaim()
+    }
+
+    /// Tells every worker to `Stop` and waits for its deque back. Shared by
+    /// `stop` and `Drop` so a `RunningQueue` dropped without an explicit
+    /// `stop` still reclaims the deques instead of leaving `WorkQueue`
+    /// wedged with every `WorkerInfo::deque` stuck at `None`. A no-op past
+    /// the first call.
+    fn reclaim(&mut self) -> Result<(), Vec<Box<Any + Send>>> {
+        if self.stopped {
+            return Ok(());
+        }
+        self.stopped = true;
+
+        let mut panics = vec!();
+        for worker in self.queue.workers.iter() {
+            worker.chan.send(WorkerMsg::Stop)
+        }
+
+        let mut deques_remaining = self.queue.workers.len();
+        while deques_remaining > 0 {
+            match self.queue.port.recv() {
+                SupervisorMsg::ReturnDeque(index, deque) => {
+                    self.queue.workers[index].deque = Some(deque);
+                    deques_remaining -= 1;
+                }
+                SupervisorMsg::Panicked(_, cause) => panics.push(cause),
+                SupervisorMsg::Finished => {}
+            }
+        }
+        self.queue.work_count = 0;
+
+        if panics.is_empty() { Ok(()) } else { Err(panics) }
+    }
+}
+
+impl<'a, QueueData: Send, WorkData: Send> Drop for RunningQueue<'a, QueueData, WorkData> {
+    fn drop(&mut self) {
+        // Make sure the workers' deques always come home, even if the
+        // caller drops the `RunningQueue` instead of calling `stop`;
+        // otherwise the next `run`/`start` panics on an already-taken
+        // deque and the workers spin forever stealing for a `Stop` that
+        // never comes.
+        let _ = self.reclaim();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{WorkQueue, WorkUnit, WorkerProxy};
+    use std::sync::atomic::{AtomicUint, SeqCst};
+
+    fn increment(amount: uint, proxy: &mut WorkerProxy<AtomicUint, uint>) {
+        proxy.user_data().fetch_add(amount, SeqCst);
+    }
+
+    #[test]
+    fn run_completes_every_work_unit() {
+        let mut queue: WorkQueue<AtomicUint, uint> = WorkQueue::new(4, AtomicUint::new(0));
+        for i in range(0u, 100) {
+            queue.push(WorkUnit { fun: increment, data: i });
+        }
+        assert!(queue.run().is_ok());
+        assert_eq!(queue.data.load(SeqCst), range(0u, 100).fold(0u, |a, b| a + b));
+    }
+
+    fn always_panics(_: uint, _: &mut WorkerProxy<(), uint>) {
+        panic!("this work unit always panics");
+    }
+
+    #[test]
+    fn run_aggregates_every_panic() {
+        let mut queue: WorkQueue<(), uint> = WorkQueue::new(2, ());
+        for _ in range(0u, 5) {
+            queue.push(WorkUnit { fun: always_panics, data: 0 });
+        }
+        match queue.run() {
+            Ok(()) => panic!("expected run() to report the panics"),
+            Err(causes) => assert_eq!(causes.len(), 5),
+        }
+    }
+
+    #[test]
+    fn start_submit_and_quiesce_drain_submitted_work() {
+        let mut queue: WorkQueue<AtomicUint, uint> = WorkQueue::new(4, AtomicUint::new(0));
+        {
+            let mut running = queue.start();
+            for i in range(0u, 200) {
+                running.submit(WorkUnit { fun: increment, data: i });
+            }
+            assert!(running.quiesce().is_empty());
+            assert!(running.stop().is_ok());
+        }
+        assert_eq!(queue.data.load(SeqCst), range(0u, 200).fold(0u, |a, b| a + b));
+    }
 }
 